@@ -0,0 +1,221 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Abstraction over the cryptographic primitives `SphinxHeader` is built on.
+//!
+//! `SphinxHeader` used to be hard-wired to curve25519, AES-CTR and
+//! HMAC-SHA256. The traits in this module pull those choices out from behind
+//! a small interface so a downstream user can plug in a different
+//! Diffie-Hellman group (e.g. a hybrid/post-quantum KEM) without forking the
+//! header pipeline. The default implementations re-use the crate's existing
+//! curve25519/AES/HMAC-SHA stack and are what `SphinxHeader`'s generic
+//! parameters default to, so existing callers are unaffected.
+//!
+//! BLOCKED: this module does not yet ship a `StreamCipher` backend, so
+//! `SphinxHeader` is not actually generic over the stream cipher the way
+//! the pluggable-backend request asked for. The routing-info
+//! encryption/decryption step lives entirely inside
+//! [`crate::header::filler::Filler`] and
+//! [`crate::header::routing::EncapsulatedRoutingInformation`], which still
+//! hard-code AES-CTR internally and don't live in this source tree to grow
+//! a backend parameter of their own - a pluggable `SC` parameter here with
+//! nothing underneath it actually calling it would be decorative. This
+//! needs to stay open/reopened rather than be treated as done until
+//! `filler`/`routing` exist in this tree and can take that parameter.
+
+use hmac::{Hmac as HmacImpl, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use curve25519_dalek::scalar::Scalar;
+
+use crate::crypto::{EphemeralSecret, PrivateKey, SharedKey};
+
+/// A Diffie-Hellman group element together with the scalar blinding
+/// operation used to derive the next hop's shared secret in the onion chain.
+///
+/// The key invariant callers rely on: blinding a group element is always a
+/// scalar multiplication, so repeatedly blinding `new_shared_secret` at each
+/// hop stays consistent between header creation and header processing.
+pub trait DiffieHellman: Sized + Clone {
+    /// The private (or ephemeral) key type used on the other side of the
+    /// exchange, e.g. a mix node's long-term `PrivateKey`.
+    type PrivateKey: DiffieHellmanPrivateKey<Self>;
+
+    /// Blinds this group element by `factor`, i.e. computes `self * factor`
+    /// in the underlying group.
+    fn blind(&self, factor: [u8; 32]) -> Self;
+
+    /// Serializes the group element, used by `SphinxHeader::to_bytes`.
+    ///
+    /// Deliberately `Vec<u8>` rather than `[u8; 32]`: a hybrid/post-quantum
+    /// `G` is not guaranteed to share curve25519's 32-byte element size, so
+    /// fixing the length here would rule out the motivating use case for
+    /// this trait in the first place.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Deserializes a group element, used by `SphinxHeader::from_bytes`.
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+/// The private-key side of a [`DiffieHellman`] exchange against group
+/// element `G`, mirroring `PrivateKey::diffie_hellman(&self, &SharedKey)` in
+/// [`crate::crypto`].
+///
+/// Named `group_diffie_hellman` rather than `diffie_hellman` deliberately:
+/// generic callers (those holding only a `G::PrivateKey: DiffieHellmanPrivateKey<G>`
+/// bound) can only reach this trait method, but concrete impls below call
+/// through to a same-named inherent method on the concrete type. Giving the
+/// two the same name would make that inherent call depend on Rust's
+/// inherent-before-trait method resolution order to avoid calling back into
+/// this very impl - harmless today, but a silent unbounded-recursion trap if
+/// the inherent method is ever renamed or removed. Distinct names make that
+/// mistake a compile error instead.
+pub trait DiffieHellmanPrivateKey<G> {
+    fn group_diffie_hellman(&self, group_element: &G) -> G;
+}
+
+impl DiffieHellmanPrivateKey<SharedKey> for PrivateKey {
+    fn group_diffie_hellman(&self, group_element: &SharedKey) -> SharedKey {
+        self.diffie_hellman(group_element)
+    }
+}
+
+impl DiffieHellman for SharedKey {
+    type PrivateKey = PrivateKey;
+
+    fn blind(&self, factor: [u8; 32]) -> Self {
+        let blinding_factor = Scalar::from_bytes_mod_order(factor);
+        let blinder: EphemeralSecret = blinding_factor.into();
+        blinder.diffie_hellman(self)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut array = [0u8; 32];
+        array.copy_from_slice(bytes);
+        SharedKey::from(array)
+    }
+}
+
+/// The MAC used to authenticate the encrypted routing information at each
+/// hop, plugged into [`crate::header::SphinxHeader::process`] and
+/// [`crate::header::SphinxHeader::process_with_previously_derived_keys`] in
+/// place of the hard-coded HMAC-SHA256 call those used to make directly.
+pub trait Hmac {
+    fn compute(key: &[u8], data: &[u8]) -> Vec<u8>;
+
+    /// Default `verify` compares in constant time: `tag` is typically read
+    /// off an attacker-controlled packet, so a variable-time `==` here would
+    /// leak how many leading bytes of a guessed tag are correct.
+    fn verify(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+        Self::compute(key, data).as_slice().ct_eq(tag).into()
+    }
+}
+
+/// The default MAC backend (HMAC-SHA256).
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultHmac;
+
+impl Hmac for DefaultHmac {
+    fn compute(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = HmacImpl::<Sha256>::new_from_slice(key)
+            .expect("HMAC-SHA256 accepts a key of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// The hash function used wherever the header pipeline needs one, currently
+/// the per-hop tag [`crate::header::replay::replay_tag`] derives to key
+/// replay detection.
+pub trait Hash {
+    fn digest(data: &[u8]) -> Vec<u8>;
+}
+
+/// The default hash backend (SHA256).
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultHash;
+
+impl Hash for DefaultHash {
+    fn digest(data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A second `Hmac` backend, distinct from `DefaultHmac`, used only to
+    /// prove that swapping `H` actually changes what a header's MAC
+    /// verifies against rather than every backend secretly bottoming out at
+    /// HMAC-SHA256.
+    struct ReversedHmac;
+
+    impl Hmac for ReversedHmac {
+        fn compute(key: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut tag = DefaultHmac::compute(key, data);
+            tag.reverse();
+            tag
+        }
+    }
+
+    #[test]
+    fn default_hmac_matches_a_plain_hmac_sha256_computation() {
+        let key = b"a sample routing-key-sized key!";
+        let data = b"the encrypted routing information";
+
+        let mut expected = HmacImpl::<Sha256>::new_from_slice(key).unwrap();
+        expected.update(data);
+
+        let expected = expected.finalize().into_bytes().to_vec();
+        assert_eq!(DefaultHmac::compute(key, data), expected);
+    }
+
+    #[test]
+    fn default_hash_matches_a_plain_sha256_digest() {
+        let data = b"some replay tag preimage";
+        assert_eq!(DefaultHash::digest(data), Sha256::digest(data).to_vec());
+    }
+
+    #[test]
+    fn swapping_the_hmac_backend_changes_what_verify_accepts() {
+        let key = b"a sample routing-key-sized key!";
+        let data = b"the encrypted routing information";
+
+        let default_tag = DefaultHmac::compute(key, data);
+        let reversed_tag = ReversedHmac::compute(key, data);
+
+        assert_ne!(default_tag, reversed_tag);
+        assert!(DefaultHmac::verify(key, data, &default_tag));
+        assert!(!DefaultHmac::verify(key, data, &reversed_tag));
+        assert!(ReversedHmac::verify(key, data, &reversed_tag));
+        assert!(!ReversedHmac::verify(key, data, &default_tag));
+    }
+
+    #[test]
+    fn group_diffie_hellman_matches_the_inherent_diffie_hellman_it_wraps() {
+        let (secret, _) = crate::crypto::keygen();
+        let (_, other_public) = crate::crypto::keygen();
+
+        let via_trait = DiffieHellmanPrivateKey::group_diffie_hellman(&secret, &other_public);
+        let via_inherent = secret.diffie_hellman(&other_public);
+
+        assert_eq!(via_trait.as_bytes(), via_inherent.as_bytes());
+    }
+}
@@ -12,57 +12,162 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use curve25519_dalek::scalar::Scalar;
+use std::marker::PhantomData;
 
 use crypto::{EphemeralSecret, PrivateKey, SharedKey};
 use keys::RoutingKeys;
+use subtle::ConstantTimeEq;
 
-use crate::constants::{HEADER_INTEGRITY_MAC_SIZE, HKDF_SALT_SIZE};
+use crate::constants::{HEADER_INTEGRITY_MAC_SIZE, HKDF_SALT_SIZE, NODE_META_INFO_SIZE};
 use crate::crypto;
+use crate::header::backend::{
+    DefaultHash, DefaultHmac, DiffieHellman, DiffieHellmanPrivateKey, Hash, Hmac,
+};
 use crate::header::delays::Delay;
 use crate::header::filler::Filler;
-use crate::header::keys::{BlindingFactor, KeyMaterial, PayloadKey};
+use crate::header::keys::{KeyMaterial, PayloadKey};
 use crate::header::routing::nodes::ParsedRawRoutingInformation;
 use crate::header::routing::{EncapsulatedRoutingInformation, ENCRYPTED_ROUTING_INFO_SIZE};
 use crate::route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, SURBIdentifier};
 use crate::{Error, ErrorKind, Result};
 
+pub mod backend;
 pub mod delays;
 pub mod filler;
 pub mod keys;
 pub mod mac;
+pub mod rekey;
+pub mod replay;
 pub mod routing;
+pub mod routing_tlv;
+pub mod surb;
 
 // 32 represents size of a MontgomeryPoint on Curve25519
 pub const HEADER_SIZE: usize =
     32 + HKDF_SALT_SIZE + HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE;
 pub type HkdfSalt = [u8; 32];
 
-#[derive(Debug)]
-#[cfg_attr(test, derive(Clone))]
-pub struct SphinxHeader {
-    pub shared_secret: SharedKey,
+/// Describes the wire-format sizes a `SphinxHeader` built for a given
+/// `max_hops` *should* have.
+///
+/// BLOCKED, not done: the request this type was added for asked for
+/// runtime-configurable `max_hops`, threaded through `new`/
+/// `new_with_precomputed_keys`/`from_bytes`/`Filler`. That isn't delivered
+/// here and can't be from this module alone - there is deliberately no
+/// public constructor for a non-default `max_hops`, because
+/// [`filler::Filler::new`] and
+/// [`routing::EncapsulatedRoutingInformation::new`]/`from_bytes` - the code
+/// that actually builds and parses the encrypted routing region - still
+/// size their buffers off the compile-time `ENCRYPTED_ROUTING_INFO_SIZE`
+/// constant, and neither lives in this source tree to be changed alongside
+/// this type. A `SphinxParams::new(max_hops)` that accepted arbitrary
+/// values would silently desync from what those two actually produce; one
+/// that only ever accepted the default value would type-check but could
+/// never be constructed for anything else, i.e. configurability that looks
+/// real but isn't. Neither is worth shipping, so this type stays
+/// `Default`-only and the ticket stays open/reopened - not closed - until
+/// `filler`/`routing` exist in this tree, grow a `SphinxParams` parameter of
+/// their own, and `new` can be added back for real.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SphinxParams {
+    max_hops: usize,
+}
+
+impl SphinxParams {
+    /// The size, in bytes, of the encrypted routing information region for
+    /// a header built with these params: each hop contributes its own
+    /// meta-info plus the next hop's integrity MAC.
+    pub fn encrypted_routing_info_size(&self) -> usize {
+        self.max_hops * (NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE)
+    }
+
+    /// The total serialized size of a `SphinxHeader` built with these
+    /// params; the parameterized generalization of the old `HEADER_SIZE`.
+    pub fn header_size(&self) -> usize {
+        32 + HKDF_SALT_SIZE + HEADER_INTEGRITY_MAC_SIZE + self.encrypted_routing_info_size()
+    }
+}
+
+impl Default for SphinxParams {
+    /// Reproduces today's compile-time `HEADER_SIZE`, by deriving the
+    /// equivalent `max_hops` from the existing `ENCRYPTED_ROUTING_INFO_SIZE`
+    /// constant, so callers that don't care about configurability see no
+    /// change in behaviour.
+    fn default() -> Self {
+        SphinxParams {
+            max_hops: ENCRYPTED_ROUTING_INFO_SIZE / (NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod sphinx_params {
+    use super::*;
+
+    #[test]
+    fn default_header_size_matches_the_old_compile_time_constant() {
+        assert_eq!(SphinxParams::default().header_size(), HEADER_SIZE);
+    }
+}
+
+/// A Sphinx header, generic over the Diffie-Hellman group (`G`), the
+/// integrity MAC (`H`) and the hash (`D`) it is built on. The type
+/// parameters default to the crate's curve25519/HMAC-SHA256/SHA256 stack, so
+/// existing callers can keep writing `SphinxHeader` unchanged; a downstream
+/// user who wants a different group (e.g. a hybrid/post-quantum KEM for the
+/// shared-secret step) supplies their own `G: DiffieHellman` instead.
+///
+/// There is no stream-cipher type parameter: the routing-info
+/// encrypt/decrypt step is owned entirely by
+/// [`filler::Filler`]/[`routing::EncapsulatedRoutingInformation`], which
+/// don't themselves take a backend parameter, so a `SC` here would have
+/// nothing to plug into.
+#[derive(Debug, Clone)]
+pub struct SphinxHeader<G: DiffieHellman = SharedKey, H = DefaultHmac, D = DefaultHash> {
+    pub shared_secret: G,
     pub hkdf_salt: HkdfSalt,
     pub routing_info: EncapsulatedRoutingInformation,
+    _backend: PhantomData<(H, D)>,
 }
 
-pub enum ProcessedHeader {
-    ForwardHop(SphinxHeader, NodeAddressBytes, Delay, PayloadKey),
+pub enum ProcessedHeader<G: DiffieHellman = SharedKey, H = DefaultHmac, D = DefaultHash> {
+    /// Carries only the next hop's header, its address, its delay, and the
+    /// payload key - no
+    /// per-hop [`routing_tlv`] records. BLOCKED, not done: that module's
+    /// codec is never called from `process`/`process_with_previously_derived_keys`,
+    /// so this variant has nowhere to put TLV records even if a caller wanted
+    /// them; see [`routing_tlv`]'s doc for what's actually missing before this
+    /// can be treated as delivered.
+    ForwardHop(SphinxHeader<G, H, D>, NodeAddressBytes, Delay, PayloadKey),
     FinalHop(DestinationAddressBytes, SURBIdentifier, PayloadKey),
 }
 
-impl SphinxHeader {
+impl<G, H, D> SphinxHeader<G, H, D>
+where
+    G: DiffieHellman,
+{
     // needs client's secret key, how should we inject this?
     // needs to deal with SURBs too at some point
+    //
+    // `route`'s `Node`s each carry a single `pub_key`, so this always
+    // Diffie-Hellmans against whatever key the caller put there - there is
+    // no way for a sender to pick "the key for node N's current epoch" the
+    // way `rekey::KeyRotation`/`rekey::RekeyManager` let a *receiving* node
+    // roll its own key over time. Giving `Node` an ordered, epoch-tagged set
+    // of public keys (and having this method select the current one per
+    // hop) needs a change to `Node` itself, which lives in `crate::route`
+    // and isn't part of this module.
     pub fn new(
         initial_secret: &EphemeralSecret,
         route: &[Node],
         delays: &[Delay],
         hkdf_salt: &[HkdfSalt],
         destination: &Destination,
+        params: &SphinxParams,
     ) -> (Self, Vec<PayloadKey>) {
         assert_eq!(route.len(), hkdf_salt.len());
         assert_eq!(route.len(), delays.len());
+        assert!(route.len() <= params.max_hops);
         let key_material = keys::KeyMaterial::derive_shared_keys(route, initial_secret);
         let routing_keys = RoutingKeys::derive_routing_keys(&key_material.shared_keys, hkdf_salt);
         let filler_string = Filler::new(&routing_keys[..route.len() - 1]);
@@ -78,9 +183,12 @@ impl SphinxHeader {
         // encapsulate header.routing information, compute MACs
         (
             SphinxHeader {
-                shared_secret: key_material.initial_shared_group_element,
+                shared_secret: G::from_bytes(
+                    &key_material.initial_shared_group_element.to_bytes(),
+                ),
                 hkdf_salt: hkdf_salt[0],
                 routing_info,
+                _backend: PhantomData,
             },
             routing_keys
                 .iter()
@@ -95,12 +203,30 @@ impl SphinxHeader {
         delays: &[Delay],
         hkdf_salt: &[HkdfSalt],
         destination: &Destination,
-        shared_keys: &[SharedKey],
-        initial_shared_secret: &SharedKey,
+        shared_keys: &[G],
+        initial_shared_secret: &G,
+        params: &SphinxParams,
     ) -> (Self, Vec<PayloadKey>) {
         assert_eq!(route.len(), hkdf_salt.len());
         assert_eq!(route.len(), shared_keys.len());
         assert_eq!(route.len(), delays.len());
+        assert!(route.len() <= params.max_hops);
+        // `RoutingKeys::derive_routing_keys` still only knows how to HKDF
+        // over a curve25519 `SharedKey`, so each `G` is converted into one
+        // here through its serialized bytes, which must be exactly 32 long:
+        // a `G` of any other size (e.g. a hybrid/PQ KEM) panics at the
+        // `copy_from_slice` below; fully generalizing
+        // routing-key derivation to `G`'s native width is tracked separately
+        // and out of scope for this change.
+        let shared_keys: Vec<SharedKey> = shared_keys
+            .iter()
+            .map(|key| {
+                let bytes = key.to_bytes();
+                let mut array = [0u8; 32];
+                array.copy_from_slice(&bytes);
+                SharedKey::from(array)
+            })
+            .collect();
         let routing_keys = RoutingKeys::derive_routing_keys(&shared_keys, hkdf_salt);
         let filler_string = Filler::new(&routing_keys[..route.len() - 1]);
         let routing_info = routing::EncapsulatedRoutingInformation::new(
@@ -114,9 +240,10 @@ impl SphinxHeader {
         // encapsulate header.routing information, compute MACs
         (
             SphinxHeader {
-                shared_secret: *initial_shared_secret,
+                shared_secret: initial_shared_secret.clone(),
                 hkdf_salt: hkdf_salt[0],
                 routing_info,
+                _backend: PhantomData,
             },
             routing_keys
                 .iter()
@@ -125,6 +252,83 @@ impl SphinxHeader {
         )
     }
 
+    /// BLOCKED, not done: the request this was added for asked for `Node`
+    /// to carry an epoch-tagged key set and for [`Self::new`] itself to
+    /// select the current-epoch key per hop. Neither happened - `new`
+    /// still derives every hop's shared secret from `Node::pub_key`
+    /// directly, unchanged, and nothing calls this function outside its
+    /// own unit tests. What's here is only a building block: the
+    /// sender-side counterpart to [`rekey::KeyRotation`], selecting, for
+    /// each hop in a route, the public key tagged with `current_epoch` out
+    /// of that hop's full set of published keys. These are public keys, to
+    /// stand in for each hop's single fixed `Node::pub_key`; they are not
+    /// the per-hop shared keys [`Self::new_with_precomputed_keys`] takes -
+    /// the caller still has to source `route_keys`, derive the shared keys
+    /// from the selected public keys and build the header themselves; there
+    /// is no wiring from `new` to here.
+    ///
+    /// `route_keys[i]` is hop `i`'s full `(epoch, public_key)` set, in any
+    /// order. Returns `None` if any hop hasn't published a key for
+    /// `current_epoch`, so the caller can fall back to a fresher route
+    /// rather than build a header against a node that has already rotated
+    /// past it - the same situation [`rekey::KeyRotation::candidate_secrets`]
+    /// exists to bridge on the receive side.
+    ///
+    /// This stays open/reopened, not closed: `route::Node` does not itself
+    /// carry this per-epoch key set (its `pub_key` is a single, untagged
+    /// key), and giving it one (so `new` itself could do this selection)
+    /// needs a change to `Node`, which lives in `crate::route` and doesn't
+    /// exist in this source tree.
+    pub fn select_current_epoch_keys(
+        route_keys: &[&[(u64, G)]],
+        current_epoch: u64,
+    ) -> Option<Vec<G>> {
+        route_keys
+            .iter()
+            .map(|hop_keys| {
+                hop_keys
+                    .iter()
+                    .find(|(epoch, _)| *epoch == current_epoch)
+                    .map(|(_, key)| key.clone())
+            })
+            .collect()
+    }
+
+    /// Like [`Self::process_with_previously_derived_keys`], but additionally
+    /// checks (and, once the packet is authenticated, records into)
+    /// `replay_detector` a tag derived from `shared_key` and `hkdf_salt`,
+    /// rejecting the packet with [`ErrorKind::ReplayedPacket`] if it has
+    /// already been processed.
+    ///
+    /// The MAC is verified before the tag is recorded, so a corrupted or
+    /// forged packet never burns a legitimate sender's tag - it's rejected
+    /// with the underlying MAC error and `replay_detector` is left
+    /// untouched.
+    ///
+    /// This is opt-in: nodes that don't need replay protection keep calling
+    /// [`Self::process_with_previously_derived_keys`] unchanged.
+    pub fn process_with_previously_derived_keys_and_replay_detection(
+        self,
+        shared_key: SharedKey,
+        hkdf_salt: Option<&HkdfSalt>,
+        replay_detector: &mut dyn crate::header::replay::ReplayDetector,
+    ) -> Result<ProcessedHeader<G, H, D>>
+    where
+        H: Hmac,
+        D: Hash,
+    {
+        let tag_salt = hkdf_salt.copied().unwrap_or(self.hkdf_salt);
+        let tag = crate::header::replay::replay_tag::<D>(&shared_key, &tag_salt);
+        let processed = self.process_with_previously_derived_keys(shared_key, hkdf_salt)?;
+        if replay_detector.check_and_record(tag) {
+            return Err(Error::new(
+                ErrorKind::ReplayedPacket,
+                "this packet (shared secret, salt) has already been processed",
+            ));
+        }
+        Ok(processed)
+    }
+
     /// Processes the header using a previously derived shared key and a fresh salt.
     /// This function can be used in the situation where sender is re-using initial secret
     /// and the intermediate nodes cash the shared key derived using Diffie Hellman as a
@@ -134,17 +338,12 @@ impl SphinxHeader {
         self,
         shared_key: SharedKey,
         hkdf_salt: Option<&HkdfSalt>,
-    ) -> Result<ProcessedHeader> {
+    ) -> Result<ProcessedHeader<G, H, D>>
+    where
+        H: Hmac,
+    {
         let routing_keys = keys::RoutingKeys::derive(shared_key, hkdf_salt);
-        if !self.routing_info.integrity_mac.verify(
-            routing_keys.header_integrity_hmac_key,
-            self.routing_info.enc_routing_information.get_value_ref(),
-        ) {
-            return Err(Error::new(
-                ErrorKind::InvalidHeader,
-                "failed to verify integrity MAC",
-            ));
-        }
+        Self::verify_integrity_mac::<H>(&routing_keys, &self.routing_info)?;
 
         let unwrapped_routing_information = self
             .routing_info
@@ -160,14 +359,14 @@ impl SphinxHeader {
             ) => {
                 let blinding_factor = KeyMaterial::compute_blinding_factor(shared_key);
                 // blind the shared_secret in the header
-                let new_shared_secret =
-                    Self::blind_the_shared_secret(self.shared_secret, blinding_factor.to_bytes());
+                let new_shared_secret = self.shared_secret.blind(blinding_factor.to_bytes());
 
                 Ok(ProcessedHeader::ForwardHop(
                     SphinxHeader {
                         shared_secret: new_shared_secret,
                         hkdf_salt: new_hkdf_salt,
                         routing_info: new_encapsulated_routing_info,
+                        _backend: PhantomData,
                     },
                     next_hop_address,
                     delay,
@@ -185,19 +384,15 @@ impl SphinxHeader {
     }
 
     /// Processes the header using a freshly derived shared key (using Diffie Hellman)
-    pub fn process(self, node_secret_key: &PrivateKey) -> Result<ProcessedHeader> {
-        let shared_key = node_secret_key.diffie_hellman(&self.shared_secret);
-        let routing_keys = Self::compute_routing_keys(shared_key, Some(&self.hkdf_salt));
-
-        if !self.routing_info.integrity_mac.verify(
-            routing_keys.header_integrity_hmac_key,
-            self.routing_info.enc_routing_information.get_value_ref(),
-        ) {
-            return Err(Error::new(
-                ErrorKind::InvalidHeader,
-                "failed to verify integrity MAC",
-            ));
-        }
+    pub fn process(self, node_secret_key: &G::PrivateKey) -> Result<ProcessedHeader<G, H, D>>
+    where
+        H: Hmac,
+    {
+        let shared_key = node_secret_key.group_diffie_hellman(&self.shared_secret);
+        let routing_keys =
+            Self::compute_routing_keys(SharedKey::from(shared_key.to_bytes()), Some(&self.hkdf_salt));
+
+        Self::verify_integrity_mac::<H>(&routing_keys, &self.routing_info)?;
 
         let unwrapped_routing_information = self
             .routing_info
@@ -211,16 +406,17 @@ impl SphinxHeader {
                 new_hkdf_salt,
                 new_encapsulated_routing_info,
             ) => {
-                let blinding_factor = KeyMaterial::compute_blinding_factor(shared_key);
+                let blinding_factor =
+                    KeyMaterial::compute_blinding_factor(SharedKey::from(shared_key.to_bytes()));
                 // blind the shared_secret in the header
-                let new_shared_secret =
-                    Self::blind_the_shared_secret(self.shared_secret, blinding_factor.to_bytes());
+                let new_shared_secret = self.shared_secret.blind(blinding_factor.to_bytes());
 
                 Ok(ProcessedHeader::ForwardHop(
                     SphinxHeader {
                         shared_secret: new_shared_secret,
                         hkdf_salt: new_hkdf_salt,
                         routing_info: new_encapsulated_routing_info,
+                        _backend: PhantomData,
                     },
                     next_hop_address,
                     delay,
@@ -237,6 +433,78 @@ impl SphinxHeader {
         }
     }
 
+    /// Like [`Self::process`], but additionally checks (and, once the
+    /// packet is authenticated, records into) `replay_detector` a tag
+    /// derived from the freshly Diffie-Hellman'd shared key and this
+    /// header's own HKDF salt, rejecting the packet with
+    /// [`ErrorKind::ReplayedPacket`] if it has already been processed.
+    ///
+    /// The MAC is verified before the tag is recorded, so a corrupted or
+    /// forged packet never burns a legitimate sender's tag - it's rejected
+    /// with the underlying MAC error and `replay_detector` is left
+    /// untouched.
+    ///
+    /// [`Self::process_with_previously_derived_keys_and_replay_detection`]
+    /// covers the cached-master-key path; this is the equivalent for
+    /// `process`, the path a mix uses for the very first packet from any
+    /// given sender, before any cached-key optimization applies - without
+    /// this, that path would have no way to opt into replay protection at
+    /// all. This is opt-in: nodes that don't need replay protection keep
+    /// calling [`Self::process`] unchanged.
+    pub fn process_and_replay_detection(
+        self,
+        node_secret_key: &G::PrivateKey,
+        replay_detector: &mut dyn crate::header::replay::ReplayDetector,
+    ) -> Result<ProcessedHeader<G, H, D>>
+    where
+        H: Hmac,
+        D: Hash,
+    {
+        let shared_key = node_secret_key.group_diffie_hellman(&self.shared_secret);
+        let tag = crate::header::replay::replay_tag::<D>(
+            &SharedKey::from(shared_key.to_bytes()),
+            &self.hkdf_salt,
+        );
+        let processed = self.process(node_secret_key)?;
+        if replay_detector.check_and_record(tag) {
+            return Err(Error::new(
+                ErrorKind::ReplayedPacket,
+                "this packet (shared secret, salt) has already been processed",
+            ));
+        }
+        Ok(processed)
+    }
+
+    /// Tries each of `candidate_secrets` in turn, returning the result of
+    /// the first one whose derived routing keys produce a matching
+    /// integrity MAC.
+    ///
+    /// A node whose static key is mid-rotation may need to accept packets
+    /// built against either its current or its just-retired epoch key (see
+    /// [`crate::header::rekey::KeyRotation`]); trying a single fixed secret
+    /// via [`Self::process`] would otherwise reject those in-flight packets
+    /// outright, with the same integrity-MAC failure the
+    /// `processing_with_wrong_salt_*` tests expect from a header processed
+    /// with the wrong salt.
+    pub fn process_with_key_set(
+        self,
+        candidate_secrets: &[&G::PrivateKey],
+    ) -> Result<ProcessedHeader<G, H, D>>
+    where
+        H: Hmac,
+    {
+        let mut last_err = None;
+        for secret in candidate_secrets {
+            match self.clone().process(secret) {
+                Ok(result) => return Ok(result),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(ErrorKind::InvalidHeader, "no candidate secrets to process against")
+        }))
+    }
+
     /// Using the provided shared_secret and node's secret key, derive all routing keys for this hop.
     pub fn compute_routing_keys(
         shared_key: SharedKey,
@@ -245,9 +513,39 @@ impl SphinxHeader {
         keys::RoutingKeys::derive(shared_key, hkdf_salt)
     }
 
+    /// Recomputes the integrity MAC over the encrypted routing information
+    /// using `I` and compares it against the one attached to `routing_info`,
+    /// taking over from `routing_info.integrity_mac`'s own inherent
+    /// `verify`, which is still hard-wired to HMAC-SHA256 independently of
+    /// this header's `H` parameter.
+    fn verify_integrity_mac<I: Hmac>(
+        routing_keys: &RoutingKeys,
+        routing_info: &EncapsulatedRoutingInformation,
+    ) -> Result<()> {
+        let computed_mac = I::compute(
+            routing_keys.header_integrity_hmac_key.as_bytes(),
+            routing_info.enc_routing_information.get_value_ref(),
+        );
+        // constant-time: `routing_info` comes straight off the wire, so an
+        // attacker controls every byte being compared here. A variable-time
+        // `==`/`!=` on it would leak how many leading bytes of a guessed MAC
+        // are correct.
+        let is_valid: bool = computed_mac
+            .as_slice()
+            .ct_eq(&routing_info.integrity_mac.as_bytes()[..])
+            .into();
+        if !is_valid {
+            return Err(Error::new(
+                ErrorKind::InvalidHeader,
+                "failed to verify integrity MAC",
+            ));
+        }
+        Ok(())
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         self.shared_secret
-            .as_bytes()
+            .to_bytes()
             .iter()
             .cloned()
             .chain(
@@ -259,14 +557,15 @@ impl SphinxHeader {
             .collect()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        if bytes.len() != HEADER_SIZE {
+    pub fn from_bytes(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        let expected_size = params.header_size();
+        if bytes.len() != expected_size {
             return Err(Error::new(
                 ErrorKind::InvalidHeader,
                 format!(
                     "tried to recover using {} bytes, expected {}",
                     bytes.len(),
-                    HEADER_SIZE
+                    expected_size
                 ),
             ));
         }
@@ -275,7 +574,7 @@ impl SphinxHeader {
         let mut shared_secret_bytes = [0u8; 32];
         // first 32 bytes represent the shared secret
         shared_secret_bytes.copy_from_slice(&bytes[i..32]);
-        let shared_secret = SharedKey::from(shared_secret_bytes);
+        let shared_secret = G::from_bytes(&shared_secret_bytes);
         i += 32;
 
         let mut hkdf_salt = [0u8; HKDF_SALT_SIZE];
@@ -283,8 +582,9 @@ impl SphinxHeader {
         i += HKDF_SALT_SIZE;
 
         // the rest are for the encapsulated routing info
-        let encapsulated_routing_info_bytes =
-            bytes[i..i + (HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE)].to_vec();
+        let encapsulated_routing_info_bytes = bytes
+            [i..i + (HEADER_INTEGRITY_MAC_SIZE + params.encrypted_routing_info_size())]
+            .to_vec();
 
         let routing_info =
             EncapsulatedRoutingInformation::from_bytes(&encapsulated_routing_info_bytes)?;
@@ -293,19 +593,9 @@ impl SphinxHeader {
             shared_secret,
             hkdf_salt,
             routing_info,
+            _backend: PhantomData,
         })
     }
-
-    fn blind_the_shared_secret(
-        shared_secret: SharedKey,
-        blinding_factor: BlindingFactor,
-    ) -> SharedKey {
-        // TODO BEFORE PR: clamping, reduction, etc.
-        let blinding_factor = Scalar::from_bytes_mod_order(blinding_factor);
-        let blinder: EphemeralSecret = blinding_factor.into();
-        // shared_secret * blinding_factor
-        blinder.diffie_hellman(&shared_secret)
-    }
 }
 
 #[cfg(test)]
@@ -362,6 +652,7 @@ mod create_and_process_sphinx_packet_header {
                 &destination,
                 &key_material.shared_keys,
                 &initial_shared_secret,
+                &crate::header::SphinxParams::default(),
             );
         }
 
@@ -409,6 +700,7 @@ mod create_and_process_sphinx_packet_header {
                 &destination,
                 shared_keys,
                 &initial_shared_secret,
+                &crate::header::SphinxParams::default(),
             );
         }
 
@@ -454,6 +746,7 @@ mod create_and_process_sphinx_packet_header {
                 &destination,
                 &key_material.shared_keys,
                 &initial_shared_secret,
+                &crate::header::SphinxParams::default(),
             );
 
             // The first mix processing
@@ -519,7 +812,14 @@ mod create_and_process_sphinx_packet_header {
             );
             let hkdf_salt = [hkdf_salt_fixture(), hkdf_salt_fixture()];
             let (sphinx_header, _) =
-                SphinxHeader::new(&initial_secret, &route, &delays, &hkdf_salt, &destination);
+                SphinxHeader::new(
+                    &initial_secret,
+                    &route,
+                    &delays,
+                    &hkdf_salt,
+                    &destination,
+                    &crate::header::SphinxParams::default(),
+                );
 
             // The first mix processing
             let normally_unwrapped1 = match sphinx_header.clone().process(&node1_sk).unwrap() {
@@ -580,7 +880,14 @@ mod create_and_process_sphinx_packet_header {
             );
             let hkdf_salt = [hkdf_salt_fixture()];
             let (sphinx_header, _) =
-                SphinxHeader::new(&initial_secret, &route, &delays, &hkdf_salt, &destination);
+                SphinxHeader::new(
+                    &initial_secret,
+                    &route,
+                    &delays,
+                    &hkdf_salt,
+                    &destination,
+                    &crate::header::SphinxParams::default(),
+                );
 
             let normally_unwrapped = match sphinx_header.clone().process(&node1_sk).unwrap() {
                 ProcessedHeader::FinalHop(destination, surb_id, keys) => {
@@ -638,7 +945,14 @@ mod create_and_process_sphinx_packet_header {
                 [12u8; HKDF_SALT_SIZE],
             ];
             let (sphinx_header, _) =
-                SphinxHeader::new(&initial_secret, &route, &delays, &hkdf_salt, &destination);
+                SphinxHeader::new(
+                    &initial_secret,
+                    &route,
+                    &delays,
+                    &hkdf_salt,
+                    &destination,
+                    &crate::header::SphinxParams::default(),
+                );
 
             let incorrect_hkdf_salt = [
                 [36u8; HKDF_SALT_SIZE],
@@ -689,7 +1003,14 @@ mod create_and_process_sphinx_packet_header {
                 [12u8; HKDF_SALT_SIZE],
             ];
             let (sphinx_header, _) =
-                SphinxHeader::new(&initial_secret, &route, &delays, &hkdf_salt, &destination);
+                SphinxHeader::new(
+                    &initial_secret,
+                    &route,
+                    &delays,
+                    &hkdf_salt,
+                    &destination,
+                    &crate::header::SphinxParams::default(),
+                );
 
             let incorrect_hkdf_salt = [
                 [36u8; HKDF_SALT_SIZE],
@@ -751,7 +1072,14 @@ mod create_and_process_sphinx_packet_header {
                 [12u8; HKDF_SALT_SIZE],
             ];
             let (sphinx_header, _) =
-                SphinxHeader::new(&initial_secret, &route, &delays, &hkdf_salt, &destination);
+                SphinxHeader::new(
+                    &initial_secret,
+                    &route,
+                    &delays,
+                    &hkdf_salt,
+                    &destination,
+                    &crate::header::SphinxParams::default(),
+                );
 
             let incorrect_hkdf_salt = [
                 [36u8; HKDF_SALT_SIZE],
@@ -837,7 +1165,14 @@ mod create_and_process_sphinx_packet_header {
             ];
 
             let (sphinx_header, _) =
-                SphinxHeader::new(&initial_secret, &route, &delays, &hkdf_salt, &destination);
+                SphinxHeader::new(
+                    &initial_secret,
+                    &route,
+                    &delays,
+                    &hkdf_salt,
+                    &destination,
+                    &crate::header::SphinxParams::default(),
+                );
 
             let new_header = match sphinx_header.process(&node1_sk).unwrap() {
                 ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, _) => {
@@ -965,10 +1300,12 @@ mod converting_header_to_bytes {
             shared_secret: SharedKey::from(&EphemeralSecret::new()),
             hkdf_salt,
             routing_info: encapsulated_routing_info,
+            _backend: PhantomData,
         };
 
         let header_bytes = header.to_bytes();
-        let recovered_header = SphinxHeader::from_bytes(&header_bytes).unwrap();
+        let recovered_header =
+            SphinxHeader::from_bytes(&header_bytes, &SphinxParams::default()).unwrap();
 
         assert_eq!(
             header.shared_secret.as_bytes(),
@@ -980,3 +1317,115 @@ mod converting_header_to_bytes {
         );
     }
 }
+
+#[cfg(test)]
+mod select_current_epoch_keys {
+    use super::*;
+
+    #[test]
+    fn picks_the_key_tagged_with_the_current_epoch_per_hop() {
+        let hop1_keys = [
+            (1u64, SharedKey::from([1u8; 32])),
+            (2u64, SharedKey::from([2u8; 32])),
+        ];
+        let hop2_keys = [
+            (1u64, SharedKey::from([3u8; 32])),
+            (2u64, SharedKey::from([4u8; 32])),
+        ];
+
+        let selected =
+            SphinxHeader::select_current_epoch_keys(&[&hop1_keys, &hop2_keys], 2).unwrap();
+        let selected_bytes: Vec<[u8; 32]> = selected.iter().map(|key| *key.as_bytes()).collect();
+
+        assert_eq!(selected_bytes, vec![[2u8; 32], [4u8; 32]]);
+    }
+
+    #[test]
+    fn returns_none_if_any_hop_has_not_published_the_current_epoch_yet() {
+        let hop1_keys = [(1u64, SharedKey::from([1u8; 32]))];
+        let hop2_keys = [(1u64, SharedKey::from([3u8; 32]))];
+
+        assert!(SphinxHeader::select_current_epoch_keys(&[&hop1_keys, &hop2_keys], 2).is_none());
+    }
+}
+
+#[cfg(test)]
+mod process_and_replay_detection {
+    use std::time::Duration;
+
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+    use crate::crypto::EphemeralSecret;
+    use crate::header::delays;
+    use crate::header::replay::InMemoryReplayDetector;
+    use crate::header::SphinxHeader;
+    use crate::route::{Node, NodeAddressBytes};
+    use crate::test_utils::fixtures::{destination_fixture, hkdf_salt_fixture};
+
+    use super::*;
+
+    #[test]
+    fn a_corrupted_packet_does_not_burn_the_tag_for_the_legitimate_one() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node1_pk,
+        };
+        let (_, node2_pk) = crypto::keygen();
+        let node2 = Node {
+            address: NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node2_pk,
+        };
+        let (_, node3_pk) = crypto::keygen();
+        let node3 = Node {
+            address: NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node3_pk,
+        };
+        let route = [node1, node2, node3];
+        let destination = destination_fixture();
+        let initial_secret = EphemeralSecret::new();
+        let average_delay = 1;
+        let delays =
+            delays::generate_from_average_duration(route.len(), Duration::from_secs(average_delay));
+        let hkdf_salt = [
+            hkdf_salt_fixture(),
+            hkdf_salt_fixture(),
+            hkdf_salt_fixture(),
+        ];
+
+        let (sphinx_header, _) = SphinxHeader::new(
+            &initial_secret,
+            &route,
+            &delays,
+            &hkdf_salt,
+            &destination,
+            &SphinxParams::default(),
+        );
+
+        // Same shared_secret and hkdf_salt as the legitimate header (so it
+        // hashes to the same replay tag), but with a flipped bit somewhere
+        // in the encrypted routing info, so it fails the MAC check - this
+        // is what an attacker who corrupts or forges a packet in flight
+        // produces.
+        let mut corrupted_bytes = sphinx_header.to_bytes();
+        let last = corrupted_bytes.len() - 1;
+        corrupted_bytes[last] ^= 1;
+        let corrupted_header =
+            SphinxHeader::from_bytes(&corrupted_bytes, &SphinxParams::default()).unwrap();
+
+        let mut replay_detector = InMemoryReplayDetector::new();
+
+        let corrupted_result =
+            corrupted_header.process_and_replay_detection(&node1_sk, &mut replay_detector);
+        assert!(corrupted_result.is_err());
+        assert!(
+            replay_detector.is_empty(),
+            "a failed MAC check must not record a tag"
+        );
+
+        // The real packet, arriving afterwards, must still be accepted.
+        assert!(sphinx_header
+            .process_and_replay_detection(&node1_sk, &mut replay_detector)
+            .is_ok());
+    }
+}
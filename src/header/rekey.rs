@@ -0,0 +1,493 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policy layer around [`crate::header::SphinxHeader::process_with_previously_derived_keys`]
+//! and [`crate::header::SphinxHeader::process_with_key_set`].
+//!
+//! [`process_with_previously_derived_keys`] lets a node cache the
+//! Diffie-Hellman master key it shares with a sender and process many
+//! packets against it using only a fresh HKDF salt each time - but nothing
+//! governs how long a cached key should live. [`RekeyManager`] tracks how
+//! many packets (and how much wall-clock time) a [`KeyEpoch`] has processed,
+//! rotates to a freshly negotiated master key once a [`RekeyPolicy`]
+//! threshold is crossed, and keeps the previous epoch alive for a grace
+//! window so in-flight packets built against it still validate.
+//!
+//! [`KeyRotation`] is the analogous policy for a node's own long-lived
+//! static keypair rather than a per-sender cached master key: it schedules
+//! when that keypair should roll over to a new epoch and, for a grace
+//! period after rotating, still hands out the just-retired secret as a
+//! candidate for [`process_with_key_set`] so packets built against the
+//! node's previous public key don't hard-fail with a MAC mismatch. Note
+//! that `Node`'s own public-key storage lives in `crate::route`, outside
+//! this module; `KeyRotation` only governs the receiving node's secret-key
+//! side of that rotation.
+//!
+//! [`process_with_previously_derived_keys`]: crate::header::SphinxHeader::process_with_previously_derived_keys
+//! [`process_with_key_set`]: crate::header::SphinxHeader::process_with_key_set
+
+use std::time::{Duration, Instant};
+
+use zeroize::Zeroize;
+
+use crate::crypto::{PrivateKey, SharedKey};
+use crate::header::{HkdfSalt, ProcessedHeader, SphinxHeader};
+use crate::Result;
+
+/// A cached master key, together with the usage counters [`RekeyPolicy`]
+/// checks to decide when it should be retired.
+///
+/// The key is held as its raw bytes (rather than a [`SharedKey`]) so that
+/// [`Drop`] can zeroize the actual storage in place; `SharedKey` itself
+/// exposes no mutable access to its bytes, so zeroizing a copy taken via
+/// `as_bytes()` would scrub the copy and leave the real key material
+/// sitting in `self` until the allocator reuses it.
+pub struct KeyEpoch {
+    master_key_bytes: [u8; 32],
+    created_at: Instant,
+    packets_processed: u64,
+}
+
+impl KeyEpoch {
+    fn new(master_key: SharedKey) -> Self {
+        KeyEpoch {
+            master_key_bytes: *master_key.as_bytes(),
+            created_at: Instant::now(),
+            packets_processed: 0,
+        }
+    }
+
+    fn master_key(&self) -> SharedKey {
+        SharedKey::from(self.master_key_bytes)
+    }
+
+    fn is_expired(&self, policy: &RekeyPolicy) -> bool {
+        self.packets_processed >= policy.max_packets || self.created_at.elapsed() >= policy.max_age
+    }
+}
+
+impl Drop for KeyEpoch {
+    fn drop(&mut self) {
+        self.master_key_bytes.zeroize();
+    }
+}
+
+/// Governs when a [`RekeyManager`] rotates to a fresh master key, and for
+/// how long a retired key is still honoured.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    /// Rotate once a master key has processed this many packets.
+    pub max_packets: u64,
+    /// Rotate once a master key has been alive for this long.
+    pub max_age: Duration,
+    /// How long a just-retired epoch is still accepted for, to cover
+    /// packets that were already in flight when the rotation happened.
+    pub grace_period: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_packets: 100_000,
+            max_age: Duration::from_secs(60 * 60),
+            grace_period: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Wraps a cached master key with an automatic rotation policy, exposing a
+/// single [`RekeyManager::process`] entry point a mix node can call for
+/// every incoming packet regardless of which epoch it was built against.
+pub struct RekeyManager {
+    current: KeyEpoch,
+    retired: Option<(KeyEpoch, Instant)>,
+    policy: RekeyPolicy,
+}
+
+impl RekeyManager {
+    pub fn new(initial_master_key: SharedKey, policy: RekeyPolicy) -> Self {
+        RekeyManager {
+            current: KeyEpoch::new(initial_master_key),
+            retired: None,
+            policy,
+        }
+    }
+
+    /// Rotates the current epoch to `new_master_key`, moving the old one
+    /// into the grace-period slot so packets built against it still
+    /// validate for a while longer.
+    ///
+    /// Only replaces the retired slot if it's empty or its grace period has
+    /// already elapsed: there is only one retired slot, so if it's still
+    /// covering an earlier epoch's in-flight packets, a second rotation
+    /// landing before that grace period ends must not evict it. In that
+    /// case the epoch that just expired out of `current` is dropped (and
+    /// so zeroized) immediately instead of getting its own grace period.
+    pub fn rotate(&mut self, new_master_key: SharedKey) {
+        self.evict_expired_retired_epoch();
+        let expired_epoch = std::mem::replace(&mut self.current, KeyEpoch::new(new_master_key));
+        if self.retired.is_none() {
+            self.retired = Some((expired_epoch, Instant::now()));
+        }
+    }
+
+    fn evict_expired_retired_epoch(&mut self) {
+        if let Some((_, retired_at)) = &self.retired {
+            if retired_at.elapsed() >= self.policy.grace_period {
+                self.retired = None;
+            }
+        }
+    }
+
+    /// Processes `header`, using the current cached epoch if it still
+    /// matches and hasn't expired, falling back to the grace-period epoch,
+    /// and finally to a full Diffie-Hellman. Internally picks whichever
+    /// cached key produces a header whose integrity MAC verifies.
+    ///
+    /// Only rotates the current epoch once the fresh DH fallback has
+    /// actually authenticated `header` (its MAC verified), and only then
+    /// with the master key that authentication derived. Deriving "the next
+    /// epoch" from whatever packet happens to be in hand the moment the
+    /// policy threshold trips - before verifying anything about it - would
+    /// let any packet, including a malformed one or one from an unrelated
+    /// sender, silently evict the legitimate session's cached epoch the
+    /// instant it expires.
+    pub fn process(
+        &mut self,
+        header: SphinxHeader,
+        node_secret_key: &PrivateKey,
+        hkdf_salt: Option<&HkdfSalt>,
+    ) -> Result<ProcessedHeader> {
+        self.evict_expired_retired_epoch();
+
+        if !self.current.is_expired(&self.policy) {
+            if let Ok(result) = header
+                .clone()
+                .process_with_previously_derived_keys(self.current.master_key(), hkdf_salt)
+            {
+                self.current.packets_processed += 1;
+                return Ok(result);
+            }
+        }
+
+        if let Some((retired, _)) = &self.retired {
+            if let Ok(result) =
+                header.clone().process_with_previously_derived_keys(retired.master_key(), hkdf_salt)
+            {
+                return Ok(result);
+            }
+        }
+
+        // Neither cached epoch matched (or the current one is past policy
+        // and due for replacement). Fall back to a full DH and let the
+        // caller see whatever error that produces; only a header that
+        // authenticates here is trusted to seed the next cached epoch.
+        let shared_secret = header.shared_secret.clone();
+        let result = header.process(node_secret_key)?;
+        if self.current.is_expired(&self.policy) {
+            let authenticated_master_key = node_secret_key.diffie_hellman(&shared_secret);
+            self.rotate(authenticated_master_key);
+        }
+        Ok(result)
+    }
+}
+
+/// Schedules when a node's own static keypair should roll over to a new
+/// epoch, and for how long the just-retired secret is still offered as a
+/// candidate to [`crate::header::SphinxHeader::process_with_key_set`].
+pub struct KeyRotation {
+    current: PrivateKey,
+    current_started_at: Instant,
+    epoch_length: Duration,
+    previous: Option<(PrivateKey, Instant)>,
+    grace_period: Duration,
+}
+
+impl KeyRotation {
+    pub fn new(initial_secret: PrivateKey, epoch_length: Duration, grace_period: Duration) -> Self {
+        KeyRotation {
+            current: initial_secret,
+            current_started_at: Instant::now(),
+            epoch_length,
+            previous: None,
+            grace_period,
+        }
+    }
+
+    /// Whether the current epoch has been alive long enough that the node
+    /// should negotiate and [`Self::rotate`] in a fresh secret.
+    pub fn is_due_for_rotation(&self) -> bool {
+        self.current_started_at.elapsed() >= self.epoch_length
+    }
+
+    /// Rolls over to `new_secret`, retaining the outgoing one as the
+    /// grace-period candidate so packets built against its still-published
+    /// public key keep processing correctly.
+    ///
+    /// Only replaces the previous slot if it's empty or its grace period
+    /// has already elapsed: there is only one previous slot, so if it's
+    /// still covering an earlier epoch's in-flight packets, a second
+    /// rotation landing before that grace period ends must not evict it.
+    /// In that case the secret that just expired out of `current` is
+    /// dropped immediately instead of getting its own grace period. See
+    /// [`RekeyManager::rotate`], which guards the analogous retired slot
+    /// for the same reason.
+    pub fn rotate(&mut self, new_secret: PrivateKey) {
+        self.evict_expired_previous();
+        let expired = std::mem::replace(&mut self.current, new_secret);
+        self.current_started_at = Instant::now();
+        if self.previous.is_none() {
+            self.previous = Some((expired, Instant::now()));
+        }
+    }
+
+    fn evict_expired_previous(&mut self) {
+        if let Some((_, retired_at)) = &self.previous {
+            if retired_at.elapsed() >= self.grace_period {
+                self.previous = None;
+            }
+        }
+    }
+
+    /// The secrets a node should try against an incoming header, in order:
+    /// the current epoch's, then (while still within its grace period) the
+    /// just-retired previous epoch's. Feed this straight into
+    /// [`crate::header::SphinxHeader::process_with_key_set`].
+    pub fn candidate_secrets(&mut self) -> Vec<&PrivateKey> {
+        self.evict_expired_previous();
+        let mut secrets = vec![&self.current];
+        if let Some((previous, _)) = &self.previous {
+            secrets.push(previous);
+        }
+        secrets
+    }
+}
+
+#[cfg(test)]
+mod rekey_manager {
+    use super::*;
+
+    fn policy_with_grace_period(grace_period: Duration) -> RekeyPolicy {
+        RekeyPolicy {
+            max_packets: u64::MAX,
+            max_age: Duration::from_secs(u64::MAX),
+            grace_period,
+        }
+    }
+
+    #[test]
+    fn rotate_does_not_evict_a_still_valid_retired_epoch() {
+        let key_a = SharedKey::from([1u8; 32]);
+        let key_b = SharedKey::from([2u8; 32]);
+        let key_c = SharedKey::from([3u8; 32]);
+
+        // a grace period long enough that neither rotation below can have
+        // let it elapse
+        let mut manager = RekeyManager::new(key_a, policy_with_grace_period(Duration::from_secs(3600)));
+
+        manager.rotate(key_b);
+        let retired_after_first_rotation = manager.retired.as_ref().unwrap().0.master_key_bytes;
+        assert_eq!(retired_after_first_rotation, *key_a.as_bytes());
+
+        // a second rotation landing well within the grace period must not
+        // evict key_a's epoch in favour of key_b's
+        manager.rotate(key_c);
+        let retired_after_second_rotation = manager.retired.as_ref().unwrap().0.master_key_bytes;
+        assert_eq!(retired_after_second_rotation, *key_a.as_bytes());
+    }
+
+    #[test]
+    fn rotate_does_evict_an_expired_retired_epoch() {
+        let key_a = SharedKey::from([1u8; 32]);
+        let key_b = SharedKey::from([2u8; 32]);
+        let key_c = SharedKey::from([3u8; 32]);
+
+        // an already-elapsed grace period, so the next rotate() call should
+        // find the retired slot expired and free to replace
+        let mut manager = RekeyManager::new(key_a, policy_with_grace_period(Duration::from_nanos(1)));
+
+        manager.rotate(key_b);
+        std::thread::sleep(Duration::from_millis(5));
+        manager.rotate(key_c);
+
+        let retired_after_second_rotation = manager.retired.as_ref().unwrap().0.master_key_bytes;
+        assert_eq!(retired_after_second_rotation, *key_b.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod rekey_manager_process {
+    use std::time::Duration;
+
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+    use crate::crypto::EphemeralSecret;
+    use crate::header::{delays, SphinxParams};
+    use crate::route::{Node, NodeAddressBytes};
+    use crate::test_utils::fixtures::{destination_fixture, hkdf_salt_fixture};
+
+    use super::*;
+
+    fn policy_expired_immediately() -> RekeyPolicy {
+        RekeyPolicy {
+            max_packets: 0,
+            max_age: Duration::from_secs(u64::MAX),
+            grace_period: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn a_corrupted_packet_arriving_at_expiry_does_not_poison_the_cached_epoch() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node1_pk,
+        };
+        let (_, node2_pk) = crypto::keygen();
+        let node2 = Node {
+            address: NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node2_pk,
+        };
+        let (_, node3_pk) = crypto::keygen();
+        let node3 = Node {
+            address: NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node3_pk,
+        };
+        let route = [node1, node2, node3];
+        let destination = destination_fixture();
+        let initial_secret = EphemeralSecret::new();
+        let delays =
+            delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+        let hkdf_salt = [
+            hkdf_salt_fixture(),
+            hkdf_salt_fixture(),
+            hkdf_salt_fixture(),
+        ];
+
+        let (legit_header, _) = SphinxHeader::new(
+            &initial_secret,
+            &route,
+            &delays,
+            &hkdf_salt,
+            &destination,
+            &SphinxParams::default(),
+        );
+
+        // Same shared_secret and salt as the legitimate header, but with a
+        // flipped bit in the encrypted routing info, so its MAC fails to
+        // verify - stands in for a malformed packet, or one from an
+        // unrelated sender, that happens to arrive right as the cache
+        // expires.
+        let mut corrupted_bytes = legit_header.to_bytes();
+        let last = corrupted_bytes.len() - 1;
+        corrupted_bytes[last] ^= 1;
+        let corrupted_header =
+            SphinxHeader::from_bytes(&corrupted_bytes, &SphinxParams::default()).unwrap();
+
+        // An initial master key unrelated to node1's actual shared secret
+        // with the sender above, standing in for whatever the cache
+        // happened to hold right before expiry.
+        let placeholder_master_key = SharedKey::from([42u8; 32]);
+        let mut manager = RekeyManager::new(placeholder_master_key, policy_expired_immediately());
+
+        // The current epoch is already expired (max_packets: 0), so the
+        // garbage packet must not be trusted to seed the next one.
+        assert!(manager
+            .process(corrupted_header, &node1_sk, None)
+            .is_err());
+        assert_eq!(
+            manager.current.master_key_bytes,
+            *placeholder_master_key.as_bytes()
+        );
+
+        // The real packet, arriving afterwards, authenticates and only then
+        // rotates the cache to its own (correctly derived) master key.
+        assert!(manager.process(legit_header.clone(), &node1_sk, None).is_ok());
+        let expected_master_key = node1_sk.diffie_hellman(&legit_header.shared_secret);
+        assert_eq!(
+            manager.current.master_key_bytes,
+            *expected_master_key.as_bytes()
+        );
+    }
+}
+
+#[cfg(test)]
+mod key_rotation {
+    use super::*;
+    use crate::crypto;
+
+    // `PrivateKey` has no `PartialEq`/`Debug` of its own, so identify which
+    // secret ended up in `previous` by comparing each candidate's
+    // Diffie-Hellman output against a fixed probe point instead.
+    fn dh_fingerprint(secret: &PrivateKey, probe: &SharedKey) -> [u8; 32] {
+        *secret.diffie_hellman(probe).as_bytes()
+    }
+
+    #[test]
+    fn rotate_does_not_evict_a_still_valid_previous_epoch() {
+        let probe = SharedKey::from([9u8; 32]);
+        let (secret_a, _) = crypto::keygen();
+        let (secret_b, _) = crypto::keygen();
+        let (secret_c, _) = crypto::keygen();
+        let fingerprint_a = dh_fingerprint(&secret_a, &probe);
+
+        // a grace period long enough that neither rotation below can have
+        // let it elapse
+        let mut rotation = KeyRotation::new(
+            secret_a,
+            Duration::from_secs(u64::MAX),
+            Duration::from_secs(3600),
+        );
+
+        rotation.rotate(secret_b);
+        assert_eq!(
+            dh_fingerprint(&rotation.previous.as_ref().unwrap().0, &probe),
+            fingerprint_a
+        );
+
+        // a second rotation landing well within the grace period must not
+        // evict secret_a's epoch in favour of secret_b's
+        rotation.rotate(secret_c);
+        assert_eq!(
+            dh_fingerprint(&rotation.previous.as_ref().unwrap().0, &probe),
+            fingerprint_a
+        );
+    }
+
+    #[test]
+    fn rotate_does_evict_an_expired_previous_epoch() {
+        let probe = SharedKey::from([9u8; 32]);
+        let (secret_a, _) = crypto::keygen();
+        let (secret_b, _) = crypto::keygen();
+        let (secret_c, _) = crypto::keygen();
+        let fingerprint_b = dh_fingerprint(&secret_b, &probe);
+
+        // an already-elapsed grace period, so the next rotate() call should
+        // find the previous slot expired and free to replace
+        let mut rotation = KeyRotation::new(
+            secret_a,
+            Duration::from_secs(u64::MAX),
+            Duration::from_nanos(1),
+        );
+
+        rotation.rotate(secret_b);
+        std::thread::sleep(Duration::from_millis(5));
+        rotation.rotate(secret_c);
+
+        assert_eq!(
+            dh_fingerprint(&rotation.previous.as_ref().unwrap().0, &probe),
+            fingerprint_b
+        );
+    }
+}
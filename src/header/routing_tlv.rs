@@ -0,0 +1,194 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bigsize-prefixed TLV codec for an optional per-hop routing extension
+//! region, plus a shift-and-pad primitive for splicing one out of a fixed-size
+//! buffer - NOT currently wired into header processing.
+//!
+//! The intended shape: every hop's mandatory fields (next-hop address,
+//! delay, HKDF salt) stay fixed-size, but a sender could append an optional
+//! extension region after them - a varint total length, followed by zero or
+//! more `(type, length, value)` records - which `ForwardHop` would surface to
+//! the caller as a `Vec<(u64, Vec<u8>)>`, with the vacated space backfilled
+//! (via [`crate::header::filler::Filler`]) so the total encrypted routing
+//! region stays a constant size.
+//!
+//! BLOCKED, not done: this module only provides [`encode_tlv_records`]/
+//! [`decode_tlv_records`] and [`extract_and_shift`] in isolation. Delivering
+//! the feature those are meant for needs `routing::nodes::ParsedRawRoutingInformation`
+//! and `routing::EncapsulatedRoutingInformation` - which own the fixed-offset
+//! parse these helpers would extend - to grow a field for the records and
+//! call into this module when unwrapping a hop. Neither type lives in this
+//! source tree, so that wiring can't be done here; until it lands elsewhere,
+//! nothing in this crate's processing pipeline calls into this module, and
+//! this ticket stays open/reopened - not closed - until `routing` exists and
+//! can be wired up for real.
+
+/// A single per-hop TLV record: an application-defined `type`, with an
+/// arbitrary-length `value`.
+pub type TlvRecord = (u64, Vec<u8>);
+
+/// Encodes a varint the way Lightning's `bigsize` does: values below `0xfd`
+/// are a single byte, values up to `u16::MAX` are prefixed with `0xfd`,
+/// values up to `u32::MAX` are prefixed with `0xfe`, and everything else is
+/// prefixed with `0xff`.
+pub fn encode_bigsize(value: u64) -> Vec<u8> {
+    if value < 0xfd {
+        vec![value as u8]
+    } else if value <= u16::MAX as u64 {
+        let mut out = vec![0xfd];
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+        out
+    } else if value <= u32::MAX as u64 {
+        let mut out = vec![0xfe];
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+        out
+    } else {
+        let mut out = vec![0xff];
+        out.extend_from_slice(&value.to_be_bytes());
+        out
+    }
+}
+
+/// Decodes a bigsize varint from the front of `bytes`, returning the value
+/// and the number of bytes it consumed.
+pub fn decode_bigsize(bytes: &[u8]) -> Option<(u64, usize)> {
+    match *bytes.first()? {
+        0xff => {
+            let value = u64::from_be_bytes(bytes.get(1..9)?.try_into().ok()?);
+            Some((value, 9))
+        }
+        0xfe => {
+            let value = u32::from_be_bytes(bytes.get(1..5)?.try_into().ok()?);
+            Some((value as u64, 5))
+        }
+        0xfd => {
+            let value = u16::from_be_bytes(bytes.get(1..3)?.try_into().ok()?);
+            Some((value as u64, 3))
+        }
+        marker => Some((marker as u64, 1)),
+    }
+}
+
+/// Serializes a set of TLV records as `length-prefixed(type, length, value)*`.
+pub fn encode_tlv_records(records: &[TlvRecord]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (record_type, value) in records {
+        body.extend(encode_bigsize(*record_type));
+        body.extend(encode_bigsize(value.len() as u64));
+        body.extend_from_slice(value);
+    }
+
+    let mut out = encode_bigsize(body.len() as u64);
+    out.extend(body);
+    out
+}
+
+/// Parses the length-prefixed TLV extension region written by
+/// [`encode_tlv_records`], returning the records and the total number of
+/// bytes consumed (the length prefix plus the records themselves), so the
+/// caller knows where the padding begins.
+pub fn decode_tlv_records(bytes: &[u8]) -> Option<(Vec<TlvRecord>, usize)> {
+    let (total_len, prefix_len) = decode_bigsize(bytes)?;
+    let mut cursor = prefix_len;
+    let end = prefix_len + total_len as usize;
+    let mut records = Vec::new();
+
+    while cursor < end {
+        let (record_type, type_len) = decode_bigsize(bytes.get(cursor..)?)?;
+        cursor += type_len;
+        let (value_len, len_len) = decode_bigsize(bytes.get(cursor..)?)?;
+        cursor += len_len;
+        let value = bytes.get(cursor..cursor + value_len as usize)?.to_vec();
+        cursor += value_len as usize;
+        records.push((record_type, value));
+    }
+
+    Some((records, cursor))
+}
+
+/// Parses a per-hop TLV extension region from the front of `remaining` (the
+/// still-encrypted-for-later-hops tail of a hop's unwrapped routing
+/// information, after its fixed-size mandatory fields have already been
+/// split off), then shifts the rest of the stream left by however many
+/// bytes the region consumed and pads the tail back out to
+/// `remaining.len()` with `pad_with`, so the next hop's fixed-offset fields
+/// land exactly where they would if no extension had been present.
+///
+/// This generalizes the left-shift-and-pad `EncapsulatedRoutingInformation`
+/// already performs for the mandatory fixed-size fields to a
+/// variable-length region; the mandatory fields themselves are still parsed
+/// by the existing fixed-offset logic, with only the leftover bytes handed
+/// here.
+pub fn extract_and_shift(remaining: &[u8], pad_with: &[u8]) -> Option<(Vec<TlvRecord>, Vec<u8>)> {
+    let (records, consumed) = decode_tlv_records(remaining)?;
+    let mut shifted = remaining.get(consumed..)?.to_vec();
+    shifted.extend_from_slice(pad_with);
+    shifted.truncate(remaining.len());
+    Some((records, shifted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bigsize_round_trips_small_and_large_values() {
+        for value in [0u64, 1, 0xfc, 0xfd, 0xffff, 0x1_0000, u32::MAX as u64, u64::MAX] {
+            let encoded = encode_bigsize(value);
+            let (decoded, consumed) = decode_bigsize(&encoded).unwrap();
+            assert_eq!(value, decoded);
+            assert_eq!(consumed, encoded.len());
+        }
+    }
+
+    #[test]
+    fn tlv_records_round_trip_through_encode_and_decode() {
+        let records: Vec<TlvRecord> = vec![(1, vec![1, 2, 3]), (42, vec![]), (7, vec![9u8; 64])];
+        let encoded = encode_tlv_records(&records);
+        let (decoded, consumed) = decode_tlv_records(&encoded).unwrap();
+        assert_eq!(records, decoded);
+        assert_eq!(consumed, encoded.len());
+    }
+
+    #[test]
+    fn decoding_stops_before_any_trailing_padding() {
+        let records: Vec<TlvRecord> = vec![(1, vec![1, 2, 3])];
+        let mut encoded = encode_tlv_records(&records);
+        let consumed_before_padding = encoded.len();
+        encoded.extend_from_slice(&[0u8; 16]);
+
+        let (decoded, consumed) = decode_tlv_records(&encoded).unwrap();
+        assert_eq!(records, decoded);
+        assert_eq!(consumed, consumed_before_padding);
+    }
+
+    #[test]
+    fn extract_and_shift_preserves_total_length_and_records() {
+        let records: Vec<TlvRecord> = vec![(1, vec![1, 2, 3])];
+        let encoded = encode_tlv_records(&records);
+        let consumed = encoded.len();
+        let mut remaining = encoded;
+        remaining.extend_from_slice(&[7u8; 32]);
+        let total_len = remaining.len();
+
+        let (decoded, shifted) = extract_and_shift(&remaining, &[0u8; 64]).unwrap();
+        assert_eq!(records, decoded);
+        assert_eq!(shifted.len(), total_len);
+        // the bytes that followed the TLV region shift to the front...
+        assert_eq!(&shifted[..total_len - consumed], &[7u8; 32][..]);
+        // ...and the caller-supplied padding fills the vacated tail
+        assert_eq!(&shifted[total_len - consumed..], &[0u8; 64][..consumed]);
+    }
+}
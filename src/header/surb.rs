@@ -0,0 +1,205 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Single-use reply blocks (SURBs).
+//!
+//! A SURB lets a recipient hand out a pre-built route back to itself without
+//! revealing that route to the sender. The recipient builds the header the
+//! same way it would for any other route (via
+//! [`SphinxHeader::new_with_precomputed_keys`]), but since it is also the
+//! *final* hop, it cannot re-derive the layered payload keys the way a
+//! normal final hop does from its own secret key alone - so it stashes the
+//! per-hop [`PayloadKey`]s it derived at construction time and uses them
+//! later to peel the reply's payload layer by layer.
+
+use crate::crypto::{EphemeralSecret, SharedKey};
+use crate::header::delays::Delay;
+use crate::header::keys::PayloadKey;
+use crate::header::{HkdfSalt, SphinxHeader, SphinxParams};
+use crate::route::{Destination, Node, NodeAddressBytes};
+use crate::Result;
+
+/// An opaque, single-use reply block, built by a recipient and handed to a
+/// would-be sender so it can reply without learning the route home.
+pub struct ReplySurb {
+    surb_header: SphinxHeader,
+    first_hop_address: NodeAddressBytes,
+    payload_keys: Vec<PayloadKey>,
+}
+
+impl ReplySurb {
+    /// The first hop the sender must forward the reply packet to.
+    pub fn first_hop_address(&self) -> NodeAddressBytes {
+        self.first_hop_address
+    }
+
+    /// The header a sender attaches to a reply payload, unmodified by them.
+    pub fn surb_header(&self) -> &SphinxHeader {
+        &self.surb_header
+    }
+
+    /// Peels every encryption layer a normal final hop would have applied
+    /// one at a time, using the keys stashed at SURB-creation time, in the
+    /// reverse of the order they were derived (i.e. the last hop's key on
+    /// the route back to the recipient first).
+    pub fn recover_plaintext_from_reply(&self, mut reply_payload: Vec<u8>) -> Result<Vec<u8>> {
+        for payload_key in self.payload_keys.iter().rev() {
+            reply_payload = crate::payload::unwrap_with_payload_key(reply_payload, payload_key)?;
+        }
+        Ok(reply_payload)
+    }
+}
+
+/// Builds [`ReplySurb`]s for a fixed recipient-chosen route back to itself.
+pub struct SurbFactory {
+    route: Vec<Node>,
+    destination: Destination,
+}
+
+impl SurbFactory {
+    pub fn new(route: Vec<Node>, destination: Destination) -> Self {
+        SurbFactory { route, destination }
+    }
+
+    /// Precomputes a `SphinxHeader` for [`Self::route`] and wraps it,
+    /// together with the per-hop payload keys and the first hop's address,
+    /// into a [`ReplySurb`] ready to be handed out to a sender.
+    pub fn build_surb(&self, delays: &[Delay], hkdf_salt: &[HkdfSalt]) -> ReplySurb {
+        let initial_secret = EphemeralSecret::new();
+        let key_material =
+            crate::header::keys::KeyMaterial::derive_shared_keys(&self.route, &initial_secret);
+        let initial_shared_secret = SharedKey::from(&initial_secret);
+
+        let (surb_header, payload_keys) = SphinxHeader::new_with_precomputed_keys(
+            &self.route,
+            delays,
+            hkdf_salt,
+            &self.destination,
+            &key_material.shared_keys,
+            &initial_shared_secret,
+            &SphinxParams::default(),
+        );
+
+        ReplySurb {
+            surb_header,
+            first_hop_address: self.route[0].address,
+            payload_keys,
+        }
+    }
+}
+
+/// The sender side of the reply path: returns a copy of the
+/// recipient-supplied [`ReplySurb`] header, for the sender to attach to an
+/// outbound payload, together with the first hop to send it to, so the
+/// sender never needs to know the route it describes.
+pub fn use_as_reply(surb: &ReplySurb) -> (SphinxHeader, NodeAddressBytes) {
+    (
+        SphinxHeader::from_bytes(&surb.surb_header().to_bytes(), &SphinxParams::default())
+            .expect("a freshly built SURB header must always deserialize"),
+        surb.first_hop_address(),
+    )
+}
+
+#[cfg(test)]
+mod reply_surb_round_trip {
+    use std::time::Duration;
+
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+    use crate::header::delays;
+    use crate::header::ProcessedHeader;
+    use crate::test_utils::fixtures::{destination_fixture, hkdf_salt_fixture};
+
+    use super::*;
+
+    #[test]
+    fn a_surb_built_for_three_mixnodes_round_trips_a_reply() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node1_pk,
+        };
+        let (node2_sk, node2_pk) = crypto::keygen();
+        let node2 = Node {
+            address: NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node2_pk,
+        };
+        let (node3_sk, node3_pk) = crypto::keygen();
+        let node3 = Node {
+            address: NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node3_pk,
+        };
+        let route = vec![node1, node2, node3];
+        let destination = destination_fixture();
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+        let hkdf_salt = [
+            hkdf_salt_fixture(),
+            hkdf_salt_fixture(),
+            hkdf_salt_fixture(),
+        ];
+
+        let factory = SurbFactory::new(route, destination);
+        let surb = factory.build_surb(&delays, &hkdf_salt);
+        let (header, first_hop) = use_as_reply(&surb);
+        assert_eq!(surb.first_hop_address(), first_hop);
+
+        let shared_key1 = node1_sk.diffie_hellman(&header.shared_secret);
+        let new_header = match header
+            .process_with_previously_derived_keys(shared_key1, Some(&hkdf_salt[0]))
+            .unwrap()
+        {
+            ProcessedHeader::ForwardHop(new_header, ..) => new_header,
+            _ => unreachable!(),
+        };
+
+        let shared_key2 = node2_sk.diffie_hellman(&new_header.shared_secret);
+        let new_header2 = match new_header
+            .process_with_previously_derived_keys(shared_key2, Some(&hkdf_salt[1]))
+            .unwrap()
+        {
+            ProcessedHeader::ForwardHop(new_header, ..) => new_header,
+            _ => unreachable!(),
+        };
+
+        let shared_key3 = node3_sk.diffie_hellman(&new_header2.shared_secret);
+        match new_header2
+            .process_with_previously_derived_keys(shared_key3, Some(&hkdf_salt[2]))
+            .unwrap()
+        {
+            ProcessedHeader::FinalHop(destination_address, ..) => {
+                assert_eq!(destination.address, destination_address)
+            }
+            _ => unreachable!(),
+        };
+
+        assert_eq!(surb.payload_keys.len(), 3);
+
+        // `recover_plaintext_from_reply` peels `payload_keys` in reverse
+        // (last hop's key first), mirroring how each hop on the way back
+        // would have layered its own key on top of whatever the previous
+        // hop already applied. Simulate that forward layering here - with
+        // the same `unwrap_with_payload_key` function, since XORing a
+        // keystream on is its own inverse - and check the recipient gets
+        // back exactly what was sent, not just that three keys exist.
+        let plaintext = b"hello from the other side".to_vec();
+        let mut layered_payload = plaintext.clone();
+        for payload_key in &surb.payload_keys {
+            layered_payload =
+                crate::payload::unwrap_with_payload_key(layered_payload, payload_key).unwrap();
+        }
+
+        let recovered = surb.recover_plaintext_from_reply(layered_payload).unwrap();
+        assert_eq!(recovered, plaintext);
+    }
+}
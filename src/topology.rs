@@ -0,0 +1,324 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Weighted route selection over a known network topology.
+//!
+//! `SphinxHeader::new` takes a fixed `&[Node]` route and offers no help
+//! choosing one. [`NetworkTopology`] tracks the nodes a client knows about
+//! together with per-link quality measurements, and [`RouteBuilder`] turns
+//! that into a route: either [`RouteBuilder::weighted_random_route`], which
+//! samples each successive hop proportional to a reliability/capacity
+//! score while forbidding repeats, or
+//! [`RouteBuilder::most_reliable_route`], a binary-heap Dijkstra over
+//! `-log(reliability)` edge weights that maximizes end-to-end success
+//! probability. Both return a [`Route`] wrapping a `Vec<Node>` ready to feed
+//! into `SphinxHeader::new`, plus the aggregate expected reliability, so a
+//! caller can retry construction until a threshold is met.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use rand::Rng;
+
+use crate::route::{Node, NodeAddressBytes};
+
+/// Measured and advertised quality metrics for a single directed link
+/// between two nodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkMetrics {
+    /// Observed one-way latency, in milliseconds.
+    pub latency_millis: f64,
+    /// Observed fraction of packets over this link that were delivered
+    /// successfully, in `(0, 1]`.
+    pub reliability: f64,
+    /// Advertised spare capacity, in arbitrary comparable units; higher is
+    /// preferred by the weighted-random sampler.
+    pub capacity: f64,
+}
+
+impl LinkMetrics {
+    /// The weighted-random sampler's score for this link: proportional to
+    /// both reliability and capacity, so a congested or flaky link is
+    /// chosen less often without being ruled out entirely.
+    fn sampling_weight(&self) -> f64 {
+        self.reliability * self.capacity.max(0.0)
+    }
+}
+
+/// A set of known nodes plus the quality of the links between them, used by
+/// [`RouteBuilder`] to select routes.
+#[derive(Default)]
+pub struct NetworkTopology {
+    nodes: HashMap<NodeAddressBytes, Node>,
+    links: HashMap<(NodeAddressBytes, NodeAddressBytes), LinkMetrics>,
+}
+
+impl NetworkTopology {
+    pub fn new() -> Self {
+        NetworkTopology::default()
+    }
+
+    pub fn add_node(&mut self, node: Node) {
+        self.nodes.insert(node.address, node);
+    }
+
+    /// Records (or overwrites) the metrics for the directed link
+    /// `from -> to`. Both endpoints must already have been added via
+    /// [`Self::add_node`].
+    pub fn set_link(&mut self, from: NodeAddressBytes, to: NodeAddressBytes, metrics: LinkMetrics) {
+        self.links.insert((from, to), metrics);
+    }
+
+    fn node(&self, address: NodeAddressBytes) -> Option<&Node> {
+        self.nodes.get(&address)
+    }
+
+    fn outgoing_links(&self, from: NodeAddressBytes) -> impl Iterator<Item = (NodeAddressBytes, LinkMetrics)> + '_ {
+        self.links
+            .iter()
+            .filter(move |((link_from, _), _)| *link_from == from)
+            .map(|((_, to), metrics)| (*to, *metrics))
+    }
+}
+
+/// A selected route, ready to feed into `SphinxHeader::new`, together with
+/// its aggregate expected reliability (the product of each hop's link
+/// reliability).
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub nodes: Vec<Node>,
+    pub expected_reliability: f64,
+}
+
+/// Builds routes over a [`NetworkTopology`] using one of two strategies.
+pub struct RouteBuilder<'a> {
+    topology: &'a NetworkTopology,
+}
+
+impl<'a> RouteBuilder<'a> {
+    pub fn new(topology: &'a NetworkTopology) -> Self {
+        RouteBuilder { topology }
+    }
+
+    /// Samples a route of exactly `hop_count` hops starting from `start`,
+    /// picking each successive hop with probability proportional to
+    /// [`LinkMetrics::sampling_weight`] among the links that haven't been
+    /// visited yet. Returns `None` if `start` is unknown or a dead end is
+    /// reached before `hop_count` hops are chosen.
+    pub fn weighted_random_route(&self, start: NodeAddressBytes, hop_count: usize) -> Option<Route> {
+        self.topology.node(start)?;
+        let mut rng = rand::thread_rng();
+        let mut current = start;
+        let mut visited = vec![start];
+        let mut nodes = Vec::with_capacity(hop_count);
+        let mut expected_reliability = 1.0;
+
+        for _ in 0..hop_count {
+            let candidates: Vec<(NodeAddressBytes, LinkMetrics)> = self
+                .topology
+                .outgoing_links(current)
+                .filter(|(to, _)| !visited.contains(to))
+                .collect();
+            let total_weight: f64 = candidates.iter().map(|(_, metrics)| metrics.sampling_weight()).sum();
+            if candidates.is_empty() || total_weight <= 0.0 {
+                return None;
+            }
+
+            let mut pick = rng.gen_range(0.0..total_weight);
+            let (next, metrics) = candidates
+                .into_iter()
+                .find(|(_, metrics)| {
+                    let weight = metrics.sampling_weight();
+                    if pick < weight {
+                        true
+                    } else {
+                        pick -= weight;
+                        false
+                    }
+                })
+                .expect("pick is always within the cumulative weight of the candidates");
+
+            nodes.push(self.topology.node(next)?.clone());
+            expected_reliability *= metrics.reliability;
+            visited.push(next);
+            current = next;
+        }
+
+        Some(Route {
+            nodes,
+            expected_reliability,
+        })
+    }
+
+    /// Finds the path from `start` to `destination` that maximizes
+    /// end-to-end delivery probability, via Dijkstra's algorithm over
+    /// `-log(reliability)` edge weights (so that minimizing total weight is
+    /// equivalent to maximizing the product of each hop's reliability).
+    pub fn most_reliable_route(
+        &self,
+        start: NodeAddressBytes,
+        destination: NodeAddressBytes,
+    ) -> Option<Route> {
+        let mut best_cost: HashMap<NodeAddressBytes, f64> = HashMap::new();
+        let mut predecessor: HashMap<NodeAddressBytes, NodeAddressBytes> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+
+        best_cost.insert(start, 0.0);
+        queue.push(HeapEntry { cost: 0.0, node: start });
+
+        while let Some(HeapEntry { cost, node }) = queue.pop() {
+            if node == destination {
+                break;
+            }
+            if cost > *best_cost.get(&node).unwrap_or(&f64::INFINITY) {
+                continue;
+            }
+
+            for (neighbor, metrics) in self.topology.outgoing_links(node) {
+                let edge_weight = -metrics.reliability.ln();
+                let candidate_cost = cost + edge_weight;
+                if candidate_cost < *best_cost.get(&neighbor).unwrap_or(&f64::INFINITY) {
+                    best_cost.insert(neighbor, candidate_cost);
+                    predecessor.insert(neighbor, node);
+                    queue.push(HeapEntry { cost: candidate_cost, node: neighbor });
+                }
+            }
+        }
+
+        if !best_cost.contains_key(&destination) {
+            return None;
+        }
+
+        let mut path = vec![destination];
+        let mut current = destination;
+        while current != start {
+            current = *predecessor.get(&current)?;
+            path.push(current);
+        }
+        path.reverse();
+
+        // `path` includes `start` as its first element (needed to walk
+        // `predecessor` back to the root); `Route::nodes` does not include
+        // `start`, matching `weighted_random_route`, which only ever pushes
+        // hops *after* `start`. Skip it here so both constructors hand
+        // `SphinxHeader::new` the same thing: the hops to route through, not
+        // the sender itself.
+        let nodes = path
+            .iter()
+            .skip(1)
+            .map(|address| self.topology.node(*address).cloned())
+            .collect::<Option<Vec<Node>>>()?;
+        let expected_reliability = (-best_cost[&destination]).exp();
+
+        Some(Route {
+            nodes,
+            expected_reliability,
+        })
+    }
+}
+
+/// A `(cost, node)` pair ordered by ascending `cost`, so a max-heap
+/// `BinaryHeap` pops the lowest-cost entry first, as Dijkstra needs.
+struct HeapEntry {
+    cost: f64,
+    node: NodeAddressBytes,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so the *lowest* cost sorts as the *greatest* `BinaryHeap`
+        // entry, making this a min-heap by cost
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+#[cfg(test)]
+mod route_builder {
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+
+    use super::*;
+
+    fn node(byte: u8) -> Node {
+        let (_, pub_key) = crypto::keygen();
+        Node {
+            address: NodeAddressBytes::from_bytes([byte; NODE_ADDRESS_LENGTH]),
+            pub_key,
+        }
+    }
+
+    /// Both route-building strategies are documented as feeding straight
+    /// into `SphinxHeader::new`, so they must agree on whether `start` is
+    /// itself a hop in `Route.nodes`. Build the same start -> middle ->
+    /// destination topology and check both return the same two hops,
+    /// neither of them `start`.
+    #[test]
+    fn weighted_random_route_and_most_reliable_route_agree_on_excluding_start() {
+        let start = node(1);
+        let middle = node(2);
+        let destination = node(3);
+
+        let mut topology = NetworkTopology::new();
+        topology.add_node(start.clone());
+        topology.add_node(middle.clone());
+        topology.add_node(destination.clone());
+        topology.set_link(
+            start.address,
+            middle.address,
+            LinkMetrics {
+                latency_millis: 10.0,
+                reliability: 0.99,
+                capacity: 1.0,
+            },
+        );
+        topology.set_link(
+            middle.address,
+            destination.address,
+            LinkMetrics {
+                latency_millis: 10.0,
+                reliability: 0.99,
+                capacity: 1.0,
+            },
+        );
+
+        let builder = RouteBuilder::new(&topology);
+
+        let random_route = builder.weighted_random_route(start.address, 2).unwrap();
+        let reliable_route = builder
+            .most_reliable_route(start.address, destination.address)
+            .unwrap();
+
+        for route in [&random_route, &reliable_route] {
+            assert_eq!(route.nodes.len(), 2);
+            assert!(route.nodes.iter().all(|node| node.address != start.address));
+        }
+
+        let reliable_addresses: Vec<_> = reliable_route.nodes.iter().map(|n| n.address).collect();
+        assert_eq!(reliable_addresses, vec![middle.address, destination.address]);
+    }
+}